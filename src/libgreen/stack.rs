@@ -0,0 +1,207 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Coroutine stacks and their management
+//!
+//! Each coroutine runs on a segment of memory allocated from the operating
+//! system with a guard page at its base. Faulting in a fresh segment (and
+//! arming its guard page) on every spawn dominates the cost of workloads that
+//! churn through millions of short-lived tasks, so the `StackPool` keeps a
+//! bounded free-list of warm segments keyed by size class and hands a recently
+//! freed one back out instead of asking the kernel for more memory.
+
+use std::libc;
+use std::os;
+use std::ptr;
+
+/// A native stack segment with a guard page mapped at its low end.
+pub struct StackSegment {
+    buf: os::MemoryMap,
+}
+
+impl StackSegment {
+    /// Maps a fresh stack segment with room for `size` usable bytes plus a
+    /// guard page, then arms that guard page. The guard page is the first page
+    /// of the mapping so that a stack overflow traps rather than silently
+    /// corrupting adjacent memory; it is mapped in addition to the requested
+    /// size so the usable region is never smaller than asked.
+    pub fn new(size: uint) -> StackSegment {
+        unsafe {
+            let total = size + os::page_size();
+            let mmap = match os::MemoryMap::new(total, [os::MapReadable,
+                                                        os::MapWritable]) {
+                Ok(map) => map,
+                Err(e) => fail!("mmap for stack of size {} failed: {}", total, e),
+            };
+
+            let mut stack = StackSegment {
+                buf: mmap,
+            };
+            stack.arm_guard_page();
+            stack
+        }
+    }
+
+    /// Re-arms the guard page at the base of this segment. This must be done
+    /// before a recycled segment is handed to a new coroutine: the page is
+    /// already protected no-access when the segment is first mapped, but
+    /// re-protecting it is cheap and keeps the invariant obvious at the reuse
+    /// site.
+    fn arm_guard_page(&mut self) {
+        unsafe {
+            let guard = self.buf.data as *libc::c_void;
+            libc::mprotect(guard, os::page_size() as libc::size_t,
+                           libc::PROT_NONE);
+        }
+    }
+
+    /// The number of usable bytes in this segment, excluding the guard page.
+    pub fn size(&self) -> uint { self.buf.len - os::page_size() }
+
+    /// The low (guard) end of the segment.
+    pub fn start(&self) -> *uint { self.buf.data as *uint }
+
+    /// The high end of the segment, one past the last usable word.
+    pub fn end(&self) -> *uint {
+        unsafe { ptr::offset(self.buf.data, self.buf.len as int) as *uint }
+    }
+}
+
+/// The default number of warm segments to retain per size class. Kept small so
+/// an idle scheduler does not pin an unbounded amount of address space.
+static DEFAULT_CAP: uint = 8;
+
+/// A free-list of warm stacks, bucketed by size class, for reuse across
+/// spawns. A segment is classified by the smallest power-of-two byte count
+/// that contains it; requests are satisfied from the matching bucket and
+/// anything handed back once a bucket is full is simply unmapped.
+pub struct StackPool {
+    // One bucket of warm segments per size class, in the order in which the
+    // classes were first handed back.
+    stacks: ~[(uint, ~[StackSegment])],
+    // High-water-mark: the most segments retained in any single bucket.
+    cap: uint,
+}
+
+impl StackPool {
+    pub fn new() -> StackPool {
+        StackPool::with_capacity(DEFAULT_CAP)
+    }
+
+    /// Creates a pool that retains at most `cap` warm segments in each size
+    /// class. A `cap` of zero disables caching entirely.
+    pub fn with_capacity(cap: uint) -> StackPool {
+        StackPool {
+            stacks: ~[],
+            cap: cap,
+        }
+    }
+
+    /// Returns a segment of at least `min_size` bytes, reusing a warm one from
+    /// the matching size class if available and otherwise faulting in a fresh
+    /// mapping. A reused segment has already had its guard page re-armed before
+    /// being returned.
+    pub fn take_segment(&mut self, min_size: uint) -> StackSegment {
+        let class = size_class(min_size);
+        match self.bucket(class) {
+            Some(bucket) if bucket.len() > 0 => {
+                let mut stack = bucket.pop();
+                stack.arm_guard_page();
+                stack
+            }
+            _ => StackSegment::new(class),
+        }
+    }
+
+    /// Returns a spent segment to the pool. The caller guarantees the segment
+    /// has been fully unwound; we keep it warm for its size class unless that
+    /// class is already at the high-water-mark, in which case the mapping is
+    /// dropped here and unmapped when its `MemoryMap` is dropped. A segment
+    /// whose size is not exactly a size class is never kept, since handing it
+    /// back out could satisfy a request larger than the segment.
+    pub fn give_segment(&mut self, stack: StackSegment) {
+        if self.cap == 0 { return }
+
+        let class = size_class(stack.size());
+        if class != stack.size() { return }
+        match self.bucket(class) {
+            Some(bucket) => {
+                if bucket.len() < self.cap { bucket.push(stack); }
+                return;
+            }
+            None => {}
+        }
+        self.stacks.push((class, ~[stack]));
+    }
+
+    fn bucket<'a>(&'a mut self, class: uint) -> Option<&'a mut ~[StackSegment]> {
+        for &(c, ref mut bucket) in self.stacks.mut_iter() {
+            if c == class { return Some(bucket); }
+        }
+        None
+    }
+
+    /// The total number of warm segments currently retained across all size
+    /// classes.
+    #[cfg(test)]
+    fn cached(&self) -> uint {
+        self.stacks.iter().fold(0, |n, &(_, ref bucket)| n + bucket.len())
+    }
+}
+
+/// Rounds a byte count up to the next power-of-two size class. Bucketing by
+/// class keeps the free-list from fragmenting into one bucket per distinct
+/// requested size while still never handing back a segment smaller than asked.
+fn size_class(size: uint) -> uint {
+    let mut class = os::page_size();
+    while class < size { class <<= 1; }
+    class
+}
+
+#[cfg(test)]
+mod test {
+    use stack::StackPool;
+
+    // These tests drive the `StackPool` API directly rather than the
+    // `configure`/`terminate` spawn path: exercising a real spawn/join loop
+    // requires a live `Scheduler` and `Coroutine` to fault in and unwind the
+    // segment, which cannot be stood up in isolation here. `configure` and
+    // `terminate` route every stack through `take_segment`/`give_segment`
+    // respectively, so covering the pool directly pins down the reuse and
+    // high-water-mark behaviour the spawn path depends on.
+
+    #[test]
+    fn reuse_within_size_class() {
+        let mut pool = StackPool::new();
+        let stack = pool.take_segment(1024);
+        let end = stack.end();
+        pool.give_segment(stack);
+
+        // A request in the same size class must hand the warm segment back
+        // rather than mapping a new one.
+        let reused = pool.take_segment(1024);
+        assert!(reused.end() == end);
+    }
+
+    #[test]
+    fn cap_bounds_the_free_list() {
+        let mut pool = StackPool::with_capacity(2);
+
+        // Hold several live segments of the same size class so that handing
+        // them all back at once would overflow the cap if it were not honored.
+        let mut held = ~[];
+        for _ in range(0, 8) { held.push(pool.take_segment(1024)); }
+        for stack in held.move_iter() { pool.give_segment(stack); }
+
+        // A tight spawn/join loop must not grow the free-list without bound:
+        // the bucket retains at most `cap` warm segments.
+        assert!(pool.cached() <= 2);
+    }
+}
@@ -24,7 +24,6 @@ use std::rt::rtio;
 use std::rt::local::Local;
 use std::rt::task::{Task, BlockedTask};
 use std::task::TaskOpts;
-use std::unstable::mutex::Mutex;
 
 use coroutine::Coroutine;
 use sched::{Scheduler, SchedHandle, RunOnce};
@@ -39,9 +38,6 @@ pub struct GreenTask {
     task: Option<~Task>,
     task_type: TaskType,
     pool_id: uint,
-
-    // See the comments in the scheduler about why this is necessary
-    nasty_deschedule_lock: Mutex,
 }
 
 pub enum TaskType {
@@ -84,23 +80,34 @@ impl GreenTask {
             task_type: task_type,
             sched: None,
             handle: None,
-            nasty_deschedule_lock: unsafe { Mutex::new() },
             task: Some(~Task::new()),
         }
     }
 
     /// Creates a new green task with the given configuration options for the
     /// contained Task object. The given stack pool is also used to allocate a
-    /// new stack for this task.
+    /// new stack for this task. The resulting task is not homed to any
+    /// particular scheduler.
     pub fn configure(pool: &mut StackPool,
                      opts: TaskOpts,
                      f: proc()) -> ~GreenTask {
+        GreenTask::configure_homed(pool, opts, AnySched, f)
+    }
+
+    /// Creates a new green task (like `configure`) pinned to the given home
+    /// scheduler. This is used to colocate a task with the scheduler owning a
+    /// particular `SchedHandle`, e.g. to share a specific event loop or
+    /// non-Send thread-local state.
+    pub fn configure_homed(pool: &mut StackPool,
+                           opts: TaskOpts,
+                           home: Home,
+                           f: proc()) -> ~GreenTask {
         let TaskOpts {
             watched: _watched,
             notify_chan, name, stack_size
         } = opts;
 
-        let mut green = GreenTask::new(pool, stack_size, f);
+        let mut green = GreenTask::new_homed(pool, stack_size, home, f);
         {
             let task = green.task.get_mut_ref();
             task.name = name;
@@ -261,25 +268,41 @@ impl GreenTask {
     //
     // Note that there is an interesting transfer of ownership going on here. We
     // must relinquish ownership of the green task, but then also send the task
-    // over the handle back to the original scheduler. In order to safely do
-    // this, we leverage the already-present "nasty descheduling lock". The
-    // reason for doing this is that each task will bounce on this lock after
-    // resuming after a context switch. By holding the lock over the enqueueing
-    // of the task, we're guaranteed that the SchedHandle's memory will be valid
-    // for this entire function.
-    //
-    // An alternative would include having incredibly cheaply cloneable handles,
-    // but right now a SchedHandle is something like 6 allocations, so it is
-    // *not* a cheap operation to clone a handle. Until the day comes that we
-    // need to optimize this, a lock should do just fine (it's completely
-    // uncontended except for when the task is rescheduled).
+    // over the handle back to the original scheduler. The subtlety is that the
+    // handle lives *inside* the task we're about to send, so sending
+    // `RunOnce(self)` would move the handle's memory to the other end of the
+    // channel out from under the in-flight send. We sidestep this by taking the
+    // handle out of the task first: the extracted handle is owned by this stack
+    // frame and so stays valid for the entire send, regardless of what happens
+    // to the task once it is enqueued. The task will arrange a fresh handle on
+    // its next deschedule.
     fn reawaken_remotely(mut ~self) {
-        unsafe {
-            let mtx = &mut self.nasty_deschedule_lock as *mut Mutex;
-            let handle = self.handle.get_mut_ref() as *mut SchedHandle;
-            (*mtx).lock();
-            (*handle).send(RunOnce(self));
-            (*mtx).unlock();
+        let mut handle = self.handle.take_unwrap();
+        handle.send(RunOnce(self));
+    }
+
+    // Spawns a sibling like the `spawn_sibling` runtime method, but pins the
+    // new task to the given home scheduler. This is the inherent counterpart
+    // used to request pinning; `spawn_sibling` itself is fixed to the `Runtime`
+    // trait signature and so cannot grow a `home` argument.
+    //
+    // A sibling that is `AnySched`, or homed to the scheduler we're running on,
+    // is bootstrapped immediately just like `spawn_sibling`. A sibling homed to
+    // a *different* scheduler must not run here: we enqueue it so the
+    // scheduler's homing machinery routes it to its owner the moment it is
+    // dequeued, and then continue running ourselves.
+    pub fn spawn_sibling_homed(mut ~self, cur_task: ~Task, opts: TaskOpts,
+                               home: Home, f: proc()) {
+        self.put_task(cur_task);
+
+        let mut sched = self.sched.take_unwrap();
+        let sibling = GreenTask::configure_homed(&mut sched.stack_pool, opts,
+                                                 home, f);
+        if sibling.homed() && !sibling.is_home_no_tls(&*sched) {
+            sched.enqueue_task(sibling);
+            self.put_with_sched(sched);
+        } else {
+            sched.run_task(self, sibling)
         }
     }
 }
@@ -420,12 +443,6 @@ impl Runtime for GreenTask {
     fn wrap(~self) -> ~Any { self as ~Any }
 }
 
-impl Drop for GreenTask {
-    fn drop(&mut self) {
-        unsafe { self.nasty_deschedule_lock.destroy(); }
-    }
-}
-
 #[cfg(test)]
 mod test {
 